@@ -0,0 +1,36 @@
+use std::path::PathBuf;
+
+/// Errors surfaced by the fractal generation pipeline, from argument
+/// validation through to writing the final image.
+#[derive(Debug, thiserror::Error)]
+pub enum SierpinskiError {
+    #[error("invalid output path")]
+    InvalidOutputPath,
+
+    #[error("output directory {0:?} does not exist")]
+    OutputDirMissing(PathBuf),
+
+    #[error("failed to save image: {0}")]
+    ImageSave(std::io::Error),
+
+    #[error("--size must be greater than zero")]
+    ZeroSize,
+
+    #[error("--quality ({quality}) must be at least as large as --threads ({threads}), so every worker plots at least one point")]
+    InsufficientQuality { quality: u32, threads: usize },
+
+    #[error("failed to read IFS config {0:?}: {1}")]
+    SystemFileRead(PathBuf, std::io::Error),
+
+    #[error("failed to load IFS config: {0}")]
+    SystemConfig(String),
+
+    #[error("IFS system must define at least one map with a positive weight")]
+    EmptySystem,
+
+    #[error("failed to build progress bar style: {0}")]
+    ProgressStyle(String),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,196 @@
+use rand::Rng;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+use crate::error::SierpinskiError;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Point {
+    pub x: f64,
+    pub y: f64,
+}
+
+/// One contraction map of an iterated function system:
+/// `x' = a*x + b*y + c`, `y' = d*x + e*y + f`, chosen with probability
+/// proportional to `weight` on each chaos-game step.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AffineMap {
+    pub coeffs: [f64; 6],
+    pub weight: f64,
+}
+
+impl AffineMap {
+    fn apply(&self, point: Point) -> Point {
+        let [a, b, c, d, e, f] = self.coeffs;
+        Point {
+            x: a * point.x + b * point.y + c,
+            y: d * point.x + e * point.y + f,
+        }
+    }
+}
+
+/// On-disk shape of a `--system <file>` TOML config, e.g.:
+/// ```toml
+/// [[map]]
+/// coeffs = [0.5, 0.0, 0.0, 0.0, 0.5, 0.0]
+/// weight = 1.0
+/// ```
+#[derive(Debug, Deserialize)]
+struct IfsConfig {
+    map: Vec<AffineMap>,
+}
+
+/// An iterated function system: a set of weighted affine maps driving the
+/// chaos game. Generalizes the old hard-coded triangle to anything that
+/// fits this schema (Barnsley ferns, Sierpinski carpets, dragon curves, ...).
+#[derive(Debug, Clone)]
+pub struct Ifs {
+    maps: Vec<AffineMap>,
+    cumulative_weights: Vec<f64>,
+}
+
+impl Ifs {
+    /// Builds an `Ifs` from its maps, rejecting systems with no maps or
+    /// whose weights are all zero (or negative) — `sample_map` would
+    /// otherwise have to sample from an empty range and panic.
+    pub fn from_maps(maps: Vec<AffineMap>) -> Result<Self, SierpinskiError> {
+        let mut running_weight = 0.0;
+        let cumulative_weights: Vec<f64> = maps
+            .iter()
+            .map(|map| {
+                running_weight += map.weight.max(0.0);
+                running_weight
+            })
+            .collect();
+
+        if cumulative_weights.last().copied().unwrap_or(0.0) <= 0.0 {
+            return Err(SierpinskiError::EmptySystem);
+        }
+
+        Ok(Self {
+            maps,
+            cumulative_weights,
+        })
+    }
+
+    /// Loads an IFS definition from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, SierpinskiError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|err| SierpinskiError::SystemFileRead(path.to_path_buf(), err))?;
+        let config: IfsConfig =
+            toml::from_str(&contents).map_err(|err| SierpinskiError::SystemConfig(err.to_string()))?;
+
+        Self::from_maps(config.map)
+    }
+
+    /// The classic Sierpinski triangle: three equally-weighted maps, each
+    /// contracting halfway towards a vertex of an isometric triangle with
+    /// the given side length.
+    pub fn sierpinski_triangle(length: f64) -> Self {
+        let vertices = [
+            (0., 0.),
+            (length, 0.),
+            (length / 2.0, length * 3f64.sqrt() / 2.0),
+        ];
+
+        let maps = vertices
+            .into_iter()
+            .map(|(vx, vy): (f64, f64)| AffineMap {
+                coeffs: [0.5, 0.0, vx / 2.0, 0.0, 0.5, vy / 2.0],
+                weight: 1.0,
+            })
+            .collect();
+
+        Self::from_maps(maps).expect("the built-in triangle always has positive weights")
+    }
+
+    fn sample_map(&self, rng: &mut impl Rng) -> &AffineMap {
+        let total_weight = *self
+            .cumulative_weights
+            .last()
+            .expect("Ifs must have at least one map");
+        let choice = rng.gen_range(0.0..total_weight);
+        let index = self.cumulative_weights.partition_point(|&w| w <= choice);
+
+        &self.maps[index]
+    }
+
+    /// Picks a map by weighted random selection and applies it, advancing
+    /// the chaos game by one step.
+    pub fn step(&self, point: Point, rng: &mut impl Rng) -> Point {
+        self.sample_map(rng).apply(point)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_maps_affine_coordinates() {
+        let map = AffineMap {
+            coeffs: [0.5, 0.0, 1.0, 0.0, 0.5, 2.0],
+            weight: 1.0,
+        };
+
+        let result = map.apply(Point { x: 4.0, y: 4.0 });
+
+        assert_eq!(result, Point { x: 3.0, y: 4.0 });
+    }
+
+    #[test]
+    fn from_maps_rejects_empty_system() {
+        assert!(matches!(
+            Ifs::from_maps(vec![]),
+            Err(SierpinskiError::EmptySystem)
+        ));
+    }
+
+    #[test]
+    fn from_maps_rejects_all_zero_weights() {
+        let maps = vec![
+            AffineMap {
+                coeffs: [0.5, 0.0, 0.0, 0.0, 0.5, 0.0],
+                weight: 0.0,
+            },
+            AffineMap {
+                coeffs: [0.5, 0.0, 0.0, 0.0, 0.5, 0.0],
+                weight: 0.0,
+            },
+        ];
+
+        assert!(matches!(
+            Ifs::from_maps(maps),
+            Err(SierpinskiError::EmptySystem)
+        ));
+    }
+
+    #[test]
+    fn sample_map_ignores_zero_weight_maps() {
+        let maps = vec![
+            AffineMap {
+                coeffs: [1.0, 0.0, 0.0, 0.0, 1.0, 0.0],
+                weight: 0.0,
+            },
+            AffineMap {
+                coeffs: [1.0, 0.0, 10.0, 0.0, 1.0, 10.0],
+                weight: 1.0,
+            },
+        ];
+        let ifs = Ifs::from_maps(maps).unwrap();
+        let mut rng = rand::thread_rng();
+
+        for _ in 0..100 {
+            assert_eq!(ifs.step(Point { x: 0.0, y: 0.0 }, &mut rng), Point { x: 10.0, y: 10.0 });
+        }
+    }
+
+    #[test]
+    fn sierpinski_triangle_has_three_equally_weighted_maps() {
+        let ifs = Ifs::sierpinski_triangle(2.0);
+
+        assert_eq!(ifs.maps.len(), 3);
+        assert!(ifs.maps.iter().all(|map| map.weight == 1.0));
+    }
+}
@@ -1,162 +1,239 @@
+mod error;
+mod ifs;
+
+use base64::Engine;
 use chrono::Utc;
 use clap::Parser;
 use console::style;
+use error::SierpinskiError;
+use ifs::{Ifs, Point};
 use image::RgbImage;
-use indicatif::{ProgressBar, ProgressIterator, ProgressState, ProgressStyle};
-use rand::prelude::*;
-use std::path::Path;
-use std::{
-    fmt::Write,
-    ops::{Add, Mul, Sub},
-};
-
-#[derive(Debug, Copy, Clone, PartialEq)]
-struct Point {
-    x: f64,
-    y: f64,
-}
-
-impl From<(f64, f64)> for Point {
-    fn from(value: (f64, f64)) -> Self {
-        Self {
-            x: value.0,
-            y: value.1,
-        }
-    }
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use std::fmt::Write;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+/// Terminals only accept escape-sequence payloads up to a few KB at a time,
+/// so the base64 blob has to be sent in chunks.
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// Number of plotted-but-discarded points each worker walks before it
+/// starts painting, so the walk has settled onto the attractor regardless
+/// of where it started.
+const WARMUP_ITERATIONS: u32 = 20;
+
+/// World-space bounding box of a batch of plotted points, used to map them
+/// onto the pixel grid once every worker has finished.
+#[derive(Debug, Clone, Copy)]
+struct Bounds {
+    min: Point,
+    max: Point,
 }
 
-impl Add for Point {
-    type Output = Self;
-
-    fn add(self, other: Self) -> Self {
+impl Bounds {
+    fn of(point: Point) -> Self {
         Self {
-            x: self.x + other.x,
-            y: self.y + other.y,
+            min: point,
+            max: point,
         }
     }
-}
-
-impl Sub for Point {
-    type Output = Self;
 
-    fn sub(self, other: Self) -> Self {
-        Self {
-            x: self.x - other.x,
-            y: self.y - other.y,
-        }
+    fn extend(&mut self, point: Point) {
+        self.min.x = self.min.x.min(point.x);
+        self.min.y = self.min.y.min(point.y);
+        self.max.x = self.max.x.max(point.x);
+        self.max.y = self.max.y.max(point.y);
     }
-}
-
-impl Mul<f64> for Point {
-    type Output = Self;
 
-    fn mul(self, other: f64) -> Self {
+    fn union(self, other: Self) -> Self {
         Self {
-            x: self.x * other,
-            y: self.y * other,
+            min: Point {
+                x: self.min.x.min(other.min.x),
+                y: self.min.y.min(other.min.y),
+            },
+            max: Point {
+                x: self.max.x.max(other.max.x),
+                y: self.max.y.max(other.max.y),
+            },
         }
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct Triangle {
-    a: Point,
-    b: Point,
-    c: Point,
-}
+/// Sizes a canvas that tightly covers `bounds`, scaled so its longer world
+/// axis spans `target_size` pixels, padded by `margin` pixels on every
+/// side. Unlike a fixed square canvas this never wastes space on an axis
+/// shorter than the other (e.g. an equilateral triangle's ~0.866 aspect
+/// ratio) and never distorts the fractal.
+fn fit_canvas(bounds: Bounds, target_size: u32, margin: u32) -> (u32, u32, f64) {
+    let world_width = (bounds.max.x - bounds.min.x).max(f64::EPSILON);
+    let world_height = (bounds.max.y - bounds.min.y).max(f64::EPSILON);
+    let scale = target_size as f64 / world_width.max(world_height);
 
-impl Triangle {
-    /// Generates a new isometric triangle
-    fn new(length: f64) -> Self {
-        let a = (0., 0.);
-        let b = (length, 0.);
-        let c = (length / 2.0, length * 3f64.sqrt() / 2.0);
+    let width = (world_width * scale).round() as u32 + 2 * margin;
+    let height = (world_height * scale).round() as u32 + 2 * margin;
 
-        Triangle::from_tuples(a, b, c)
-    }
+    (width, height, scale)
+}
 
-    fn from_points(a: Point, b: Point, c: Point) -> Self {
-        Triangle { a, b, c }
-    }
+/// Maps a world-space point into the canvas produced by [`fit_canvas`],
+/// clamping to the buffer's bounds so a point landing exactly on the
+/// bounding box's edge never overflows `put_pixel` instead of silently
+/// being dropped.
+fn map_to_canvas(
+    point: Point,
+    bounds: Bounds,
+    scale: f64,
+    margin: u32,
+    width: u32,
+    height: u32,
+) -> (u32, u32) {
+    let x = (point.x - bounds.min.x) * scale + margin as f64;
+    let y = (point.y - bounds.min.y) * scale + margin as f64;
+
+    (
+        x.round().clamp(0.0, (width - 1) as f64) as u32,
+        y.round().clamp(0.0, (height - 1) as f64) as u32,
+    )
+}
 
-    fn from_tuples(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> Self {
-        Triangle {
-            a: a.into(),
-            b: b.into(),
-            c: c.into(),
-        }
-    }
+/// One worker's share of the chaos game: the plotted points (in world
+/// space, not yet mapped to pixels) plus the bounding box they span.
+struct Walk {
+    points: Vec<Point>,
+    bounds: Bounds,
 }
 
 struct Sierpinski {
-    triangle: Triangle,
-    rng: ThreadRng,
+    ifs: Ifs,
     iterations: u32,
     size: u32,
+    margin: u32,
+    threads: usize,
 }
 
 impl Sierpinski {
-    fn random_barycentric_coordinates(&mut self) -> (f64, f64) {
-        let r1 = self.rng.gen_range(0.0..=1.0);
-        let r2 = self.rng.gen_range(0.0..=1.0 - r1);
-
-        (r1, r2)
-    }
-
-    fn get_triangle_random_point(&mut self) -> Point {
-        let (u, v) = self.random_barycentric_coordinates();
-        // P = A + u * (B - A) + v * (C - A)
-        self.triangle.a
-            + (self.triangle.b - self.triangle.a) * u
-            + (self.triangle.c - self.triangle.a) * v
+    fn init(ifs: Ifs, size: u32, margin: u32, iterations: u32, threads: usize) -> Self {
+        Self {
+            ifs,
+            iterations,
+            size,
+            margin,
+            threads,
+        }
     }
 
-    fn get_random_vertex(&mut self) -> Point {
-        let arr = [self.triangle.a, self.triangle.b, self.triangle.c];
+    /// Runs one worker's share of the chaos game on its own RNG,
+    /// collecting the resulting points for later mapping onto the canvas.
+    /// The attractor is ergodic, so independent walks started from the
+    /// same point with different RNGs all converge onto the same fractal
+    /// and can simply be pooled afterwards.
+    fn walk(ifs: &Ifs, iterations: u32, pb: &ProgressBar) -> Walk {
+        let mut rng = rand::thread_rng();
+        let mut point = Point { x: 0.0, y: 0.0 };
+        let mut points = Vec::with_capacity(iterations as usize);
+        let mut bounds: Option<Bounds> = None;
+
+        for i in 0..(WARMUP_ITERATIONS + iterations) {
+            point = ifs.step(point, &mut rng);
+
+            if i >= WARMUP_ITERATIONS {
+                bounds = Some(match bounds {
+                    Some(mut b) => {
+                        b.extend(point);
+                        b
+                    }
+                    None => Bounds::of(point),
+                });
+                points.push(point);
+                pb.inc(1);
+            }
+        }
 
-        arr[self.rng.gen_range(0..arr.len())]
+        Walk {
+            points,
+            bounds: bounds.expect("iterations must be greater than zero"),
+        }
     }
 
-    fn init(size: u32, iterations: u32) -> Self {
-        Self {
-            triangle: Triangle::new(size.into()),
-            rng: rand::thread_rng(),
-            iterations,
-            size,
+    fn gen_fractal(self) -> Result<RgbImage, SierpinskiError> {
+        if self.size == 0 {
+            return Err(SierpinskiError::ZeroSize);
         }
-    }
 
-    fn gen_fractal(mut self) -> RgbImage {
         println!(
             "{} {}Generating fractal...",
             style("[1/3]").bold().dim(),
             console::Emoji("🌀  ", "")
         );
 
-        //Setup progress bar
-        let pb = ProgressBar::new(self.iterations.into());
-        pb.set_style(
-            ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos:>7}/{len:7} ({eta})")
-                .unwrap()
-                .with_key("eta", |state: &ProgressState, w: &mut dyn Write| write!(w, "{:.1}s", state.eta().as_secs_f64()).unwrap())
-                .progress_chars("#>-")
-        );
+        let n_threads = self.threads.max(1);
+        let per_thread = self.iterations / n_threads as u32;
+
+        //Setup progress bar, shared across every worker thread
+        let pb = ProgressBar::new((per_thread * n_threads as u32).into());
+        let style = ProgressStyle::with_template(
+            "{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {pos:>7}/{len:7} ({eta})",
+        )
+        .map_err(|err| SierpinskiError::ProgressStyle(err.to_string()))?
+        .with_key("eta", |state: &ProgressState, w: &mut dyn Write| {
+            let _ = write!(w, "{:.1}s", state.eta().as_secs_f64());
+        })
+        .progress_chars("#>-");
+        pb.set_style(style);
+
+        let walks = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..n_threads)
+                .map(|_| {
+                    let pb = pb.clone();
+                    let ifs = &self.ifs;
+                    scope.spawn(move || Self::walk(ifs, per_thread, &pb))
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("worker thread panicked"))
+                .collect::<Vec<_>>()
+        });
 
-        //Fractal generation
-        let mut imgbuf = RgbImage::new(self.size, self.size);
-        let mut point = self.get_triangle_random_point();
+        pb.finish_with_message(format!("Finished in {:?}.", pb.elapsed()));
 
-        for _ in (0..self.iterations).progress_with(pb.clone()) {
-            let vertex = self.get_random_vertex();
-            let (x, y) = ((point.x + vertex.x) / 2.0, (point.y + vertex.y) / 2.0);
-            imgbuf.put_pixel(x as u32, y as u32, image::Rgb([255, 255, 255]));
-            point = Point { x, y };
-        }
+        let bounds = walks
+            .iter()
+            .map(|walk| walk.bounds)
+            .reduce(Bounds::union)
+            .expect("at least one worker thread runs");
 
-        pb.finish_with_message(format!("Finished in {:?}.", pb.elapsed()));
+        let (width, height, scale) = fit_canvas(bounds, self.size, self.margin);
+
+        let buffers = walks.into_iter().map(|walk| {
+            let mut imgbuf = RgbImage::new(width, height);
+            for point in walk.points {
+                let (x, y) = map_to_canvas(point, bounds, scale, self.margin, width, height);
+                imgbuf.put_pixel(x, y, image::Rgb([255, 255, 255]));
+            }
+            imgbuf
+        });
+
+        Ok(merge_buffers(width, height, buffers))
+    }
+}
 
-        imgbuf
+/// Merges the per-thread buffers by taking the per-pixel maximum, which
+/// for this black-and-white fractal is equivalent to OR-ing the white
+/// pixels plotted by each independent worker.
+fn merge_buffers(width: u32, height: u32, buffers: impl Iterator<Item = RgbImage>) -> RgbImage {
+    let mut merged = RgbImage::new(width, height);
+
+    for buffer in buffers {
+        for (x, y, pixel) in buffer.enumerate_pixels() {
+            if pixel[0] > merged.get_pixel(x, y)[0] {
+                merged.put_pixel(x, y, *pixel);
+            }
+        }
     }
+
+    merged
 }
 
 #[derive(Parser, Debug)]
@@ -170,19 +247,113 @@ struct Args {
 
     #[arg(short = 'd', long, default_value_t = {String::from("./")})]
     output_directory: String,
+
+    /// Number of worker threads splitting the chaos game iterations,
+    /// defaulting to the number of available cores.
+    #[arg(short, long, default_value_t = default_threads())]
+    threads: usize,
+
+    /// Preview the fractal in the terminal (Kitty graphics protocol) in
+    /// addition to saving it, so --size/--quality can be iterated on
+    /// without opening the output file.
+    #[arg(short = 'p', long)]
+    preview: bool,
+
+    /// TOML file describing a custom iterated function system to render
+    /// instead of the built-in Sierpinski triangle. See `Ifs::load`.
+    #[arg(long)]
+    system: Option<PathBuf>,
+
+    /// Padding, in pixels, added around the fractal's bounding box on
+    /// every side of the output canvas.
+    #[arg(long, default_value_t = 0)]
+    margin: u32,
 }
 
-fn check_path(path: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    let parent_dir = path.parent().ok_or("Invalid path")?;
+/// Below this, the chaos game hasn't run long enough to fill in the
+/// fractal's shape and the output tends to look like sparse noise.
+const MIN_RECOMMENDED_QUALITY: u32 = 10_000;
+
+fn validate_args(args: &Args) -> Result<(), SierpinskiError> {
+    if args.size == 0 {
+        return Err(SierpinskiError::ZeroSize);
+    }
+
+    // `gen_fractal` floors `--threads` at 1 before dividing the iteration
+    // budget across workers, so that's the minimum quality must clear too.
+    let effective_threads = args.threads.max(1);
+    if (args.quality as usize) < effective_threads {
+        return Err(SierpinskiError::InsufficientQuality {
+            quality: args.quality,
+            threads: effective_threads,
+        });
+    }
+
+    if args.quality < MIN_RECOMMENDED_QUALITY {
+        eprintln!(
+            "{}: --quality {} is very low, the fractal may look sparse",
+            console::style("warning").yellow(),
+            args.quality
+        );
+    }
+
+    Ok(())
+}
+
+fn default_threads() -> usize {
+    std::thread::available_parallelism()
+        .map(std::num::NonZeroUsize::get)
+        .unwrap_or(1)
+}
+
+fn check_path(path: &Path) -> Result<(), SierpinskiError> {
+    let parent_dir = path.parent().ok_or(SierpinskiError::InvalidOutputPath)?;
     if !parent_dir.exists() {
-        return Err(format!("Directory {:?} does not exist", parent_dir).into());
+        return Err(SierpinskiError::OutputDirMissing(parent_dir.to_path_buf()));
     }
 
     Ok(())
 }
 
-fn main() {
+/// Whether stdout is a terminal (as opposed to a pipe or file redirect).
+/// This is only a TTY check: it does not detect whether the terminal
+/// emulator actually understands the Kitty graphics protocol.
+fn supports_kitty_preview() -> bool {
+    std::io::stdout().is_terminal()
+}
+
+/// Renders `image` directly in the terminal using the Kitty graphics
+/// protocol: https://sw.kovidgoyal.net/kitty/graphics-protocol/
+///
+/// The PNG-encoded image is base64'd and streamed as a series of
+/// `ESC _G ... ESC \` chunks, since terminals impose a cap on how much a
+/// single escape sequence may carry. Every chunk but the last sets `m=1`
+/// to announce more data is coming; the first chunk also carries the
+/// action (`a=T`, transmit-and-display) and format (`f=100`, PNG) keys.
+fn print_kitty_image(image: &RgbImage) -> Result<(), Box<dyn std::error::Error>> {
+    let mut png_bytes = Vec::new();
+    image.write_to(&mut std::io::Cursor::new(&mut png_bytes), image::ImageFormat::Png)?;
+    let encoded = base64::engine::general_purpose::STANDARD.encode(png_bytes);
+
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(KITTY_CHUNK_SIZE).collect();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let m = if i == chunks.len() - 1 { 0 } else { 1 };
+        let payload = std::str::from_utf8(chunk)?;
+
+        if i == 0 {
+            print!("\x1b_Ga=T,f=100,m={m};{payload}\x1b\\");
+        } else {
+            print!("\x1b_Gm={m};{payload}\x1b\\");
+        }
+    }
+    println!();
+
+    Ok(())
+}
+
+fn run() -> Result<(), SierpinskiError> {
     let args = Args::parse();
+    validate_args(&args)?;
 
     let file_name = format!(
         "{0}_{1}x{1}_{2}.png",
@@ -192,17 +363,15 @@ fn main() {
     );
 
     let path = Path::new(&args.output_directory).join(file_name);
+    check_path(&path)?;
 
-    match check_path(&path) {
-        Ok(()) => (),
-        Err(err) => {
-            eprintln!("{}: {err}", console::style("error").red(),);
-            std::process::exit(1);
-        }
-    }
+    let ifs = match &args.system {
+        Some(path) => Ifs::load(path)?,
+        None => Ifs::sierpinski_triangle(args.size.into()),
+    };
 
-    let sier = Sierpinski::init(args.size, args.quality);
-    let image = sier.gen_fractal();
+    let sier = Sierpinski::init(ifs, args.size, args.margin, args.quality, args.threads);
+    let image = sier.gen_fractal()?;
 
     println!(
         "{} {}Saving file...",
@@ -211,13 +380,147 @@ fn main() {
     );
     image
         .save(&path)
-        .map(|()| {
-            println!(
-                "{} {}Saved to: {}",
-                style("[3/3]").bold().dim(),
-                console::Emoji("✅  ", ""),
-                path.display()
-            )
-        })
-        .expect("An error occured while trying to save the file");
+        .map_err(|err| SierpinskiError::ImageSave(std::io::Error::other(err)))?;
+    println!(
+        "{} {}Saved to: {}",
+        style("[3/3]").bold().dim(),
+        console::Emoji("✅  ", ""),
+        path.display()
+    );
+
+    if args.preview {
+        if supports_kitty_preview() {
+            if let Err(err) = print_kitty_image(&image) {
+                eprintln!(
+                    "{}: failed to render preview: {err}",
+                    console::style("warning").yellow(),
+                );
+            }
+        } else {
+            eprintln!(
+                "{}: stdout is not a Kitty-capable terminal, skipping preview",
+                console::style("warning").yellow(),
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> std::process::ExitCode {
+    if let Err(err) = run() {
+        eprintln!("{}: {err}", console::style("error").red());
+        return std::process::ExitCode::FAILURE;
+    }
+
+    std::process::ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_path_rejects_missing_directory() {
+        let path = Path::new("/does/not/exist/out.png");
+
+        assert!(matches!(
+            check_path(path),
+            Err(SierpinskiError::OutputDirMissing(_))
+        ));
+    }
+
+    #[test]
+    fn check_path_accepts_existing_directory() {
+        let path = std::env::temp_dir().join("out.png");
+
+        assert!(check_path(&path).is_ok());
+    }
+
+    #[test]
+    fn validate_args_rejects_quality_below_thread_count() {
+        let args = Args {
+            size: 50,
+            quality: 2,
+            output_directory: String::from("./"),
+            threads: 4,
+            preview: false,
+            system: None,
+            margin: 0,
+        };
+
+        assert!(matches!(
+            validate_args(&args),
+            Err(SierpinskiError::InsufficientQuality {
+                quality: 2,
+                threads: 4,
+            })
+        ));
+    }
+
+    #[test]
+    fn validate_args_rejects_zero_quality_with_a_single_thread() {
+        let args = Args {
+            size: 50,
+            quality: 0,
+            output_directory: String::from("./"),
+            threads: 1,
+            preview: false,
+            system: None,
+            margin: 0,
+        };
+
+        assert!(matches!(
+            validate_args(&args),
+            Err(SierpinskiError::InsufficientQuality {
+                quality: 0,
+                threads: 1,
+            })
+        ));
+    }
+
+    #[test]
+    fn gen_fractal_rejects_zero_size() {
+        let sier = Sierpinski::init(Ifs::sierpinski_triangle(10.0), 0, 0, 100, 1);
+
+        assert!(matches!(sier.gen_fractal(), Err(SierpinskiError::ZeroSize)));
+    }
+
+    #[test]
+    fn fit_canvas_scales_longer_axis_to_target_size_and_adds_margin() {
+        let bounds = Bounds {
+            min: Point { x: 0.0, y: 0.0 },
+            max: Point { x: 10.0, y: 5.0 },
+        };
+
+        let (width, height, scale) = fit_canvas(bounds, 100, 2);
+
+        assert_eq!(scale, 10.0);
+        assert_eq!(width, 100 + 4);
+        assert_eq!(height, 50 + 4);
+    }
+
+    #[test]
+    fn map_to_canvas_clamps_points_on_the_boundary() {
+        let bounds = Bounds {
+            min: Point { x: 0.0, y: 0.0 },
+            max: Point { x: 10.0, y: 10.0 },
+        };
+
+        let (x, y) = map_to_canvas(Point { x: 10.0, y: 10.0 }, bounds, 10.0, 0, 100, 100);
+
+        assert_eq!((x, y), (99, 99));
+    }
+
+    #[test]
+    fn merge_buffers_takes_the_per_pixel_maximum() {
+        let mut a = RgbImage::new(2, 2);
+        a.put_pixel(0, 0, image::Rgb([255, 255, 255]));
+        let b = RgbImage::new(2, 2);
+
+        let merged = merge_buffers(2, 2, [a, b].into_iter());
+
+        assert_eq!(*merged.get_pixel(0, 0), image::Rgb([255, 255, 255]));
+        assert_eq!(*merged.get_pixel(1, 1), image::Rgb([0, 0, 0]));
+    }
 }